@@ -0,0 +1,359 @@
+//! Disk IO abstraction used when unpacking packages.
+//!
+//! Decompressing a tar entry and writing it to disk are independent pieces
+//! of work: one is CPU bound, the other is IO bound. `Executor` lets the
+//! caller submit completed (decompressed) `Item`s and get back an iterator
+//! of `CompletedItem`s as they finish, without caring whether the work
+//! happened inline or on a background thread. `ImmediateUnpacker` performs
+//! the work on the calling thread (used on memory constrained hosts, where
+//! we can't afford to buffer many not-yet-written entries); `ThreadedExecutor`
+//! hands writes off to a worker pool so that decompressing the next entry
+//! overlaps with disk IO for the previous one.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+
+use threadpool::ThreadPool;
+
+/// The maximum number of bytes of not-yet-written file content we'll buffer
+/// before blocking `dispatch` to apply backpressure. Without this, a large
+/// package like rust-docs can have its entire decompressed content
+/// buffered in memory while the workers are still catching up on writes.
+const IN_FLIGHT_BYTE_BUDGET: usize = 64 * 1024 * 1024;
+
+/// A unit of disk work to be carried out by an `Executor`.
+pub struct Item {
+    /// The path the operation applies to.
+    pub full_path: PathBuf,
+    pub kind: Kind,
+}
+
+pub enum Kind {
+    /// Create `full_path` (and any missing ancestors).
+    Directory,
+    /// Write `content` to `full_path`, creating or truncating the file, and
+    /// then apply `mode` (the tar entry's permission bits), if given. We
+    /// have to apply it ourselves because `fs::write` always creates with
+    /// the process' default mode, and tar headers frequently mark files
+    /// (e.g. toolchain binaries nested under a `dir` component) executable.
+    File(Vec<u8>, Option<u32>),
+}
+
+impl Item {
+    pub fn make_dir(full_path: PathBuf) -> Self {
+        Item {
+            full_path,
+            kind: Kind::Directory,
+        }
+    }
+
+    pub fn write_file(full_path: PathBuf, content: Vec<u8>, mode: Option<u32>) -> Self {
+        Item {
+            full_path,
+            kind: Kind::File(content, mode),
+        }
+    }
+
+    /// Bytes of buffered content this item is holding onto, for
+    /// backpressure accounting.
+    fn buffered_size(&self) -> usize {
+        match &self.kind {
+            Kind::File(content, _) => content.len(),
+            Kind::Directory => 0,
+        }
+    }
+}
+
+/// The outcome of an `Item` once an `Executor` has actioned it.
+pub struct CompletedItem {
+    pub full_path: PathBuf,
+    pub result: io::Result<()>,
+    pub size: usize,
+    /// Whether this was a `Kind::File` write, as opposed to a directory
+    /// creation, so callers tracking unpack progress can count files
+    /// without caring about our internal directory bookkeeping.
+    pub is_file: bool,
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+fn perform(item: Item) -> CompletedItem {
+    let size = item.buffered_size();
+    let is_file = matches!(item.kind, Kind::File(_, _));
+    let Item { full_path, kind } = item;
+    let result = match kind {
+        Kind::Directory => fs::create_dir_all(&full_path),
+        Kind::File(content, mode) => fs::write(&full_path, &content).and_then(|_| match mode {
+            Some(mode) => set_mode(&full_path, mode),
+            None => Ok(()),
+        }),
+    };
+    CompletedItem {
+        full_path,
+        result,
+        size,
+        is_file,
+    }
+}
+
+/// Something that can take `Item`s of disk work and (eventually) hand back
+/// `CompletedItem`s for them. Implementations may action work inline or
+/// overlap it with further dispatch on background threads.
+pub trait Executor {
+    /// Submit `item` for processing. May block if the implementation is
+    /// applying backpressure. Returns any items that completed as a side
+    /// effect of this call (may be empty).
+    fn dispatch(&mut self, item: Item) -> Box<dyn Iterator<Item = CompletedItem> + '_>;
+
+    /// Block until all outstanding work submitted so far has completed,
+    /// returning the results.
+    fn join(&mut self) -> Box<dyn Iterator<Item = CompletedItem> + '_>;
+
+    /// Return whatever work has completed so far, without blocking.
+    fn completed(&mut self) -> Box<dyn Iterator<Item = CompletedItem> + '_>;
+
+    /// Block until the directory at `dir` (previously submitted via
+    /// `Item::make_dir`) has actually been created. Callers that need to
+    /// act on a path outside of this executor (symlinks, hardlinks, or any
+    /// other operation we don't model as an `Item`) must call this before
+    /// touching `dir`, since `dispatch` on a directory only guarantees the
+    /// creation has been *scheduled*, not completed.
+    fn wait_for(&mut self, dir: &Path) -> Box<dyn Iterator<Item = CompletedItem> + '_>;
+}
+
+/// Performs all disk IO on the calling thread. This is the original
+/// (pre-parallel) behaviour, kept around for memory constrained hosts where
+/// we can't afford to let decompression run ahead of writes.
+#[derive(Default)]
+pub struct ImmediateUnpacker {}
+
+impl ImmediateUnpacker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Executor for ImmediateUnpacker {
+    fn dispatch(&mut self, item: Item) -> Box<dyn Iterator<Item = CompletedItem> + '_> {
+        Box::new(std::iter::once(perform(item)))
+    }
+
+    fn join(&mut self) -> Box<dyn Iterator<Item = CompletedItem> + '_> {
+        Box::new(std::iter::empty())
+    }
+
+    fn completed(&mut self) -> Box<dyn Iterator<Item = CompletedItem> + '_> {
+        Box::new(std::iter::empty())
+    }
+
+    fn wait_for(&mut self, _dir: &Path) -> Box<dyn Iterator<Item = CompletedItem> + '_> {
+        // Every `dispatch` already ran (and so completed) synchronously.
+        Box::new(std::iter::empty())
+    }
+}
+
+/// Farms disk IO out to a worker pool so that decompressing the next tar
+/// entry overlaps with writing the previous one. Applies a bounded
+/// in-flight byte budget: `dispatch` blocks once that many bytes of
+/// not-yet-written content are outstanding.
+///
+/// Directory creation is a first-class `Item` too, and is tracked: a file
+/// whose parent directory hasn't finished being created yet is held back
+/// (still counted against the byte budget) rather than submitted to the
+/// pool, and is only released once that directory's `CompletedItem` comes
+/// back. This lets the caller just push directories and files as it meets
+/// them in tar order, instead of doing a synchronous `create_dir_all` per
+/// directory on the hot path.
+pub struct ThreadedExecutor {
+    pool: ThreadPool,
+    tx: Sender<CompletedItem>,
+    rx: Receiver<CompletedItem>,
+    in_flight: Arc<(Mutex<usize>, Condvar)>,
+    /// Directories that have finished being created.
+    created_dirs: HashSet<PathBuf>,
+    /// Directories currently being created, and the file items that are
+    /// waiting on them.
+    pending_dirs: HashMap<PathBuf, Vec<Item>>,
+}
+
+impl Default for ThreadedExecutor {
+    fn default() -> Self {
+        let pool = threadpool::Builder::new()
+            .thread_name("diskio".into())
+            .build();
+        let (tx, rx) = channel();
+        ThreadedExecutor {
+            pool,
+            tx,
+            rx,
+            in_flight: Arc::new((Mutex::new(0), Condvar::new())),
+            created_dirs: HashSet::new(),
+            pending_dirs: HashMap::new(),
+        }
+    }
+}
+
+impl ThreadedExecutor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Block until there's room in the in-flight byte budget for `needed`
+    /// more bytes, returning any completions observed while waiting (so the
+    /// caller doesn't lose them).
+    ///
+    /// Bytes buffered behind a not-yet-created parent directory (see
+    /// `route`) count against this budget too, but no worker thread ever
+    /// touches them — they're only released from `pending_dirs` by
+    /// `release_waiters`, which this same thread runs from `drain`. If we
+    /// just slept on the condvar, a budget filled entirely by such buffered
+    /// bytes would never shrink: nothing would be left to notify us. So
+    /// cap the sleep and drain (releasing any now-unblocked children)
+    /// ourselves on every wakeup instead of only waiting to be woken.
+    fn wait_for_budget(&mut self, needed: usize) -> Vec<CompletedItem> {
+        let mut completed = Vec::new();
+        loop {
+            {
+                let (lock, cvar) = &*self.in_flight;
+                let mut in_flight = lock.lock().unwrap();
+                if *in_flight == 0 || *in_flight + needed <= IN_FLIGHT_BYTE_BUDGET {
+                    *in_flight += needed;
+                    return completed;
+                }
+                let _ = cvar
+                    .wait_timeout(in_flight, std::time::Duration::from_millis(50))
+                    .unwrap();
+            }
+            completed.extend(self.drain());
+        }
+    }
+
+    /// Hand `item` to a worker thread right away.
+    fn submit(&self, item: Item) {
+        let tx = self.tx.clone();
+        let in_flight = self.in_flight.clone();
+        let needed = item.buffered_size();
+        self.pool.execute(move || {
+            let _span = crate::utils::tracing::global()
+                .map(|t| t.span("perform", "diskio", item.full_path.to_str()));
+            let completed = perform(item);
+            let (lock, cvar) = &*in_flight;
+            {
+                let mut in_flight = lock.lock().unwrap();
+                *in_flight -= needed;
+            }
+            cvar.notify_all();
+            // The receiving end lives at least as long as this executor,
+            // so a send failure only happens during shutdown; nothing
+            // useful to do but drop the result.
+            let _ = tx.send(completed);
+        });
+    }
+
+    /// If `completed` was a directory creation, mark it done and release
+    /// any file items that were waiting on it.
+    fn release_waiters(&mut self, completed: &CompletedItem) {
+        if let Some(waiting) = self.pending_dirs.remove(&completed.full_path) {
+            self.created_dirs.insert(completed.full_path.clone());
+            for child in waiting {
+                self.submit(child);
+            }
+        }
+    }
+
+    /// Drain whatever has completed, releasing any file items that were
+    /// waiting on a directory that just finished.
+    fn drain(&mut self) -> Vec<CompletedItem> {
+        let completed: Vec<_> = self.rx.try_iter().collect();
+        for item in &completed {
+            self.release_waiters(item);
+        }
+        completed
+    }
+
+    /// Route `item`, buffering it if it's a file whose parent directory
+    /// hasn't finished being created yet.
+    fn route(&mut self, item: Item) {
+        match &item.kind {
+            Kind::Directory => {
+                if self.created_dirs.contains(&item.full_path)
+                    || self.pending_dirs.contains_key(&item.full_path)
+                {
+                    // Already created, or already being created: nothing
+                    // further to do.
+                    return;
+                }
+                self.pending_dirs.insert(item.full_path.clone(), Vec::new());
+                self.submit(item);
+            }
+            Kind::File(..) => {
+                let parent = item.full_path.parent().map(Path::to_path_buf);
+                match parent {
+                    Some(parent) if self.pending_dirs.contains_key(&parent) => {
+                        self.pending_dirs.get_mut(&parent).unwrap().push(item);
+                    }
+                    _ => self.submit(item),
+                }
+            }
+        }
+    }
+}
+
+impl Executor for ThreadedExecutor {
+    fn dispatch(&mut self, item: Item) -> Box<dyn Iterator<Item = CompletedItem> + '_> {
+        let mut completed = self.wait_for_budget(item.buffered_size());
+        self.route(item);
+        completed.extend(self.drain());
+        Box::new(completed.into_iter())
+    }
+
+    fn join(&mut self) -> Box<dyn Iterator<Item = CompletedItem> + '_> {
+        // Draining can itself release buffered items onto the pool, so keep
+        // going until the pool is both idle and has nothing left to report.
+        let mut completed = Vec::new();
+        loop {
+            self.pool.join();
+            let batch = self.drain();
+            if batch.is_empty() && self.pool.active_count() == 0 && self.pool.queued_count() == 0
+            {
+                completed.extend(batch);
+                break;
+            }
+            completed.extend(batch);
+        }
+        Box::new(completed.into_iter())
+    }
+
+    fn completed(&mut self) -> Box<dyn Iterator<Item = CompletedItem> + '_> {
+        Box::new(self.drain().into_iter())
+    }
+
+    fn wait_for(&mut self, dir: &Path) -> Box<dyn Iterator<Item = CompletedItem> + '_> {
+        let mut completed = self.drain();
+        while self.pending_dirs.contains_key(dir) {
+            match self.rx.recv() {
+                Ok(item) => {
+                    self.release_waiters(&item);
+                    completed.push(item);
+                }
+                // The worker holding this directory's job is gone; nothing
+                // more will ever arrive for it.
+                Err(_) => break,
+            }
+        }
+        Box::new(completed.into_iter())
+    }
+}
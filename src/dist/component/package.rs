@@ -2,12 +2,14 @@
 //! for installing from a directory or tarball to an installation
 //! prefix, represented by a `Components` instance.
 
+use crate::diskio::{self, CompletedItem, Executor, Item as IoItem};
 use crate::dist::component::components::*;
 use crate::dist::component::transaction::*;
 
 use crate::dist::temp;
 use crate::errors::*;
 use crate::utils::notifications::Notification;
+use crate::utils::tracing;
 use crate::utils::utils;
 
 use std::collections::HashSet;
@@ -81,6 +83,13 @@ impl Package for DirectoryPackage {
         short_name: Option<&str>,
         tx: Transaction<'a>,
     ) -> Result<Transaction<'a>> {
+        // Declared before `_span` so it drops (and flushes) after the span
+        // closes: locals drop in reverse declaration order, and a flush
+        // that runs before the top-level span's `'E'` event is recorded
+        // would leave that span unclosed in the emitted trace.
+        let _flush_guard = tracing::flush_on_drop();
+        let _span = tracing::global().map(|t| t.span("install", "package", Some(name)));
+
         let actual_name = if self.components.contains(name) {
             name
         } else if let Some(n) = short_name {
@@ -101,6 +110,8 @@ impl Package for DirectoryPackage {
             let path = part.1;
             let src_path = root.join(&path);
 
+            let _part_span = tracing::global().map(|t| t.span(&part.0, "package", path.to_str()));
+
             match &*part.0 {
                 "file" => {
                     if self.copy {
@@ -119,6 +130,8 @@ impl Package for DirectoryPackage {
                 _ => return Err(ErrorKind::CorruptComponent(name.to_owned()).into()),
             }
 
+            let _perms_span =
+                tracing::global().map(|t| t.span("set_file_perms", "package", path.to_str()));
             set_file_perms(&target.prefix().path().join(path), &src_path)?;
         }
 
@@ -214,108 +227,77 @@ impl<'a> TarPackage<'a> {
     }
 }
 
-#[cfg(windows)]
-mod unpacker {
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use std::sync::Arc;
-    use threadpool;
+/// Picks the `diskio::Executor` to use for unpacking. Threaded IO overlaps
+/// decompression of the next tar entry with writing the previous one, but
+/// buffers not-yet-written content in memory; fall back to the immediate,
+/// single-threaded path when asked to conserve memory.
+fn make_executor(immediate: bool) -> Box<dyn Executor> {
+    if immediate {
+        Box::new(diskio::ImmediateUnpacker::new())
+    } else {
+        Box::new(diskio::ThreadedExecutor::new())
+    }
+}
 
-    use crate::utils::notifications::Notification;
+/// Below this much available memory, prefer writing inline over buffering
+/// decompressed entries for a worker pool: the threaded executor's gain is
+/// overlap, not less total memory use, so on a genuinely constrained host
+/// it's better to give that memory to the xz/gzip decoder instead.
+const LOW_MEMORY_THRESHOLD: u64 = 1024 * 1024 * 1024;
 
-    pub struct Unpacker<'a> {
-        n_files: Arc<AtomicUsize>,
-        pool: threadpool::ThreadPool,
-        notify_handler: Option<&'a dyn Fn(Notification<'_>)>,
-    }
+fn should_unpack_immediately() -> bool {
+    available_memory().map_or(false, |available| available < LOW_MEMORY_THRESHOLD)
+}
 
-    impl<'a> Unpacker<'a> {
-        pub fn new(notify_handler: Option<&'a dyn Fn(Notification<'_>)>) -> Self {
-            // Defaults to hardware thread count threads; this is suitable for
-            // our needs as IO bound operations tend to show up as write latencies
-            // rather than close latencies, so we don't need to look at
-            // more threads to get more IO dispatched at this stage in the process.
-            let pool = threadpool::Builder::new()
-                .thread_name("CloseHandle".into())
-                .build();
-            Unpacker {
-                n_files: Arc::new(AtomicUsize::new(0)),
-                pool: pool,
-                notify_handler: notify_handler,
-            }
-        }
+/// Tracks real unpack progress (files and bytes actually written to disk)
+/// and reports it through dedicated `Notification::Unpacking*` events,
+/// rather than synthesizing it from how many file handles are still
+/// pending close.
+struct UnpackProgress<'a> {
+    files_done: u64,
+    bytes_done: u64,
+    notify_handler: Option<&'a dyn Fn(Notification<'_>)>,
+}
 
-        pub fn handle(&mut self, unpacked: tar::Unpacked) {
-            if let tar::Unpacked::File(f) = unpacked {
-                self.n_files.fetch_add(1, Ordering::Relaxed);
-                let n_files = self.n_files.clone();
-                self.pool.execute(move || {
-                    drop(f);
-                    n_files.fetch_sub(1, Ordering::Relaxed);
-                });
-            }
+impl<'a> UnpackProgress<'a> {
+    fn new(notify_handler: Option<&'a dyn Fn(Notification<'_>)>) -> Self {
+        if let Some(handler) = notify_handler {
+            // Tar archives are read as a stream, so we don't know the total
+            // file count up front; the CLI tracker treats 0 as "unknown".
+            handler(Notification::UnpackingStarted(0));
+        }
+        UnpackProgress {
+            files_done: 0,
+            bytes_done: 0,
+            notify_handler,
         }
     }
 
-    impl<'a> Drop for Unpacker<'a> {
-        fn drop(&mut self) {
-            // Some explanation is in order. Even though the tar we are reading from (if
-            // any) will have had its FileWithProgress download tracking
-            // completed before we hit drop, that is not true if we are unwinding due to a
-            // failure, where the logical ownership of the progress bar is
-            // ambiguous, and as the tracker itself is abstracted out behind
-            // notifications etc we cannot just query for that. So: we assume no
-            // more reads of the underlying tar will take place: either the
-            // error unwinding will stop reads, or we completed; either way, we
-            // notify finished to the tracker to force a reset to zero; we set
-            // the units to files, show our progress, and set our units back
-            // afterwards. The largest archives today - rust docs - have ~20k
-            // items, and the download tracker's progress is confounded with
-            // actual handling of data today, we synthesis a data buffer and
-            // pretend to have bytes to deliver.
-            self.notify_handler
-                .map(|handler| handler(Notification::DownloadFinished));
-            self.notify_handler
-                .map(|handler| handler(Notification::DownloadPushUnits("handles")));
-            let mut prev_files = self.n_files.load(Ordering::Relaxed);
-            self.notify_handler.map(|handler| {
-                handler(Notification::DownloadContentLengthReceived(
-                    prev_files as u64,
-                ))
-            });
-            if prev_files > 50 {
-                println!("Closing {} deferred file handles", prev_files);
-            }
-            let buf: Vec<u8> = vec![0; prev_files];
-            assert!(32767 > prev_files);
-            let mut current_files = prev_files;
-            while current_files != 0 {
-                use std::thread::sleep;
-                sleep(std::time::Duration::from_millis(100));
-                prev_files = current_files;
-                current_files = self.n_files.load(Ordering::Relaxed);
-                let step_count = prev_files - current_files;
-                self.notify_handler.map(|handler| {
-                    handler(Notification::DownloadDataReceived(&buf[0..step_count]))
+    fn apply(&mut self, completed: CompletedItem) -> Result<()> {
+        completed
+            .result
+            .chain_err(|| ErrorKind::ExtractingPackage)?;
+
+        if completed.is_file {
+            self.files_done += 1;
+            self.bytes_done += completed.size as u64;
+            if let Some(handler) = self.notify_handler {
+                handler(Notification::UnpackingProgress {
+                    files_done: self.files_done,
+                    bytes_done: self.bytes_done,
                 });
             }
-            self.pool.join();
-            self.notify_handler
-                .map(|handler| handler(Notification::DownloadFinished));
-            self.notify_handler
-                .map(|handler| handler(Notification::DownloadPopUnits));
         }
+
+        Ok(())
     }
 }
 
-#[cfg(not(windows))]
-mod unpacker {
-    use crate::utils::notifications::Notification;
-    pub struct Unpacker {}
-    impl Unpacker {
-        pub fn new<'a>(_notify_handler: Option<&'a dyn Fn(Notification<'_>)>) -> Unpacker {
-            Unpacker {}
+impl<'a> Drop for UnpackProgress<'a> {
+    fn drop(&mut self) {
+        if let Some(handler) = self.notify_handler {
+            handler(Notification::UnpackingFinished);
         }
-        pub fn handle(&mut self, _unpacked: tar::Unpacked) {}
     }
 }
 
@@ -324,11 +306,12 @@ fn unpack_without_first_dir<'a, R: Read>(
     path: &Path,
     notify_handler: Option<&'a dyn Fn(Notification<'_>)>,
 ) -> Result<()> {
-    let mut unpacker = unpacker::Unpacker::new(notify_handler);
+    let _span = tracing::global().map(|t| t.span("unpack_without_first_dir", "unpack", None));
+    let mut progress = UnpackProgress::new(notify_handler);
+    let mut executor = make_executor(should_unpack_immediately());
     let entries = archive
         .entries()
         .chain_err(|| ErrorKind::ExtractingPackage)?;
-    let mut checked_parents: HashSet<PathBuf> = HashSet::new();
 
     for entry in entries {
         let mut entry = entry.chain_err(|| ErrorKind::ExtractingPackage)?;
@@ -337,32 +320,62 @@ fn unpack_without_first_dir<'a, R: Read>(
             let path = path.chain_err(|| ErrorKind::ExtractingPackage)?;
             path.into_owned()
         };
+        let _entry_span =
+            tracing::global().map(|t| t.span("unpack_entry", "unpack", relpath.to_str()));
         let mut components = relpath.components();
         // Throw away the first path component
         components.next();
         let full_path = path.join(&components.as_path());
 
-        // Create the full path to the entry if it does not exist already
+        // Push the parent directory as its own item rather than `stat`ing
+        // and `create_dir_all`ing it here: the executor tracks which
+        // directories it has already created (or is creating) and holds
+        // back file writes until their directory is ready, so files flow
+        // to workers as soon as they can rather than gating on a
+        // synchronous directory creation in this loop.
         if let Some(parent) = full_path.parent() {
-            if !checked_parents.contains(parent) {
-                checked_parents.insert(parent.to_owned());
-                // It would be nice to optimise this stat out, but the tar could be like so:
-                // a/deep/file.txt
-                // a/file.txt
-                // which would require tracking the segments rather than a simple hash.
-                // Until profile shows that one stat per dir is a problem (vs one stat per file)
-                // leave till later.
-
-                if !parent.exists() {
-                    std::fs::create_dir_all(&parent).chain_err(|| ErrorKind::ExtractingPackage)?
-                }
+            for completed in executor.dispatch(IoItem::make_dir(parent.to_owned())) {
+                progress.apply(completed)?;
             }
         }
-        entry.set_preserve_mtime(false);
-        entry
-            .unpack(&full_path)
-            .map(|unpacked| unpacker.handle(unpacked))
-            .chain_err(|| ErrorKind::ExtractingPackage)?;
+
+        if entry.header().entry_type().is_file() {
+            // Read the (already decompressed) entry fully into memory and
+            // hand the write off to the executor, so the next entry can
+            // start decompressing while this one is still being written.
+            // The mode travels with the buffer since `fs::write` always
+            // creates with the process' default permissions, which would
+            // otherwise silently drop the executable bit tar recorded.
+            let mode = entry.header().mode().ok();
+            let mut content = Vec::with_capacity(entry.size() as usize);
+            entry
+                .read_to_end(&mut content)
+                .chain_err(|| ErrorKind::ExtractingPackage)?;
+            for completed in executor.dispatch(IoItem::write_file(full_path, content, mode)) {
+                progress.apply(completed)?;
+            }
+        } else {
+            // Symlinks, hardlinks and explicit directory entries aren't
+            // modeled as executor `Item`s, so `entry.unpack` runs right
+            // here rather than on a worker. A hardlink (or some symlink)
+            // entry can reference an earlier file entry whose write may
+            // still be buffered or running on the pool, since file writes
+            // no longer land on disk in submission order — so it's not
+            // enough to just wait for our own parent directory; join the
+            // executor to flush every outstanding write first, guaranteeing
+            // whatever this entry links to is actually present.
+            for completed in executor.join() {
+                progress.apply(completed)?;
+            }
+            entry.set_preserve_mtime(false);
+            entry
+                .unpack(&full_path)
+                .chain_err(|| ErrorKind::ExtractingPackage)?;
+        }
+    }
+
+    for completed in executor.join() {
+        progress.apply(completed)?;
     }
 
     Ok(())
@@ -426,20 +439,94 @@ impl<'a> Package for TarGzPackage<'a> {
 pub struct TarXzPackage<'a>(TarPackage<'a>);
 
 impl<'a> TarXzPackage<'a> {
-    pub fn new<R: Read>(
+    /// Unpacks an xz-compressed tarball. If decompressing it would need
+    /// more memory than this host has available, automatically falls back
+    /// to the equivalent gzip tarball obtained from `gz_stream` (only
+    /// invoked when the fallback is actually needed) instead of letting
+    /// liblzma thrash or OOM a small machine.
+    pub fn new<R: Read, G: Read>(
         stream: R,
+        gz_stream: impl FnOnce() -> Result<G>,
         temp_cfg: &'a temp::Cfg,
         notify_handler: Option<&'a dyn Fn(Notification<'_>)>,
-    ) -> Result<Self> {
-        let stream = xz2::read::XzDecoder::new(stream);
-        Ok(TarXzPackage(TarPackage::new(
-            stream,
-            temp_cfg,
-            notify_handler,
-        )?))
+    ) -> Result<Box<dyn Package + 'a>> {
+        // `.tar.xz` artifacts are the `.xz` container format, not
+        // legacy LZMA-alone, so this must be a stream decoder (checksum
+        // flag 0 = don't require a particular integrity check), not
+        // `new_lzma_decoder` — that only understands LZMA-alone and
+        // would fail every install with a format error rather than the
+        // memory-limit error we're trying to detect below.
+        let memlimit = xz_memlimit();
+        let lzma_stream = xz2::stream::Stream::new_stream_decoder(memlimit, 0)
+            .chain_err(|| ErrorKind::ExtractingPackage)?;
+        let stream = xz2::read::XzDecoder::new_stream(stream, lzma_stream);
+
+        let result = TarPackage::new(stream, temp_cfg, notify_handler)
+            .map(TarXzPackage)
+            .map_err(|e| {
+                if is_xz_memlimit_error(&e) {
+                    ErrorKind::XzMemoryLimitExceeded.into()
+                } else {
+                    e
+                }
+            });
+
+        match result {
+            Ok(pkg) => Ok(Box::new(pkg)),
+            Err(Error(ErrorKind::XzMemoryLimitExceeded, _)) => Ok(Box::new(TarGzPackage::new(
+                gz_stream()?,
+                temp_cfg,
+                notify_handler,
+            )?)),
+            Err(e) => Err(e),
+        }
     }
 }
 
+/// The amount of memory, in bytes, we're willing to let the xz decoder use
+/// for its LZMA dictionary. Derived from currently available system
+/// memory, leaving headroom since decompression runs concurrently with the
+/// rest of installation. Deliberately has no high floor: the whole point
+/// is to let the decoder fail (and fall back to gzip) on hosts that
+/// genuinely don't have much RAM to spare.
+fn xz_memlimit() -> u64 {
+    const MIN_MEMLIMIT: u64 = 64 * 1024 * 1024;
+    const ASSUMED_AVAILABLE_IF_UNKNOWN: u64 = 512 * 1024 * 1024;
+    const HEADROOM_FRACTION: u64 = 2;
+
+    let available = available_memory().unwrap_or(ASSUMED_AVAILABLE_IF_UNKNOWN);
+    std::cmp::max(available / HEADROOM_FRACTION, MIN_MEMLIMIT)
+}
+
+/// Best-effort detection of currently available system memory; `None` if we
+/// don't know how to ask on this platform.
+#[cfg(target_os = "linux")]
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo.lines().find_map(|line| {
+        let rest = line.strip_prefix("MemAvailable:")?;
+        let kb: u64 = rest.trim().trim_end_matches("kB").trim().parse().ok()?;
+        Some(kb * 1024)
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_memory() -> Option<u64> {
+    None
+}
+
+/// Whether `err` (or one of its causes) is liblzma telling us, via xz2's
+/// `Error::MemLimit`, that it hit the memory limit we gave it.
+fn is_xz_memlimit_error(err: &Error) -> bool {
+    err.iter().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::get_ref)
+            .and_then(|source| source.downcast_ref::<xz2::stream::Error>())
+            .map_or(false, |xz_err| matches!(xz_err, xz2::stream::Error::MemLimit))
+    })
+}
+
 impl<'a> Package for TarXzPackage<'a> {
     fn contains(&self, component: &str, short_name: Option<&str>) -> bool {
         self.0.contains(component, short_name)
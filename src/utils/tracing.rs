@@ -0,0 +1,200 @@
+//! A minimal Chrome-tracing-format profiler.
+//!
+//! When `RUSTUP_PROFILE` names a file, the major phases of an install
+//! (each `install` call, per-`ComponentPart` moves, `set_file_perms`
+//! walks, the tar extraction loop and each tar entry, disk writes on the
+//! unpack workers) are wrapped in duration events and
+//! written out as the `{"name","cat","ph":"B"/"E","ts","pid","tid"}` JSON
+//! array that `chrome://tracing`/Perfetto loads, so stalls and serialization
+//! can be seen without a debugger. Reading the package manifest has no span
+//! of its own; its time shows only inside the enclosing `install` span.
+//! When the variable isn't set this is a
+//! no-op: `global()` returns `None` and callers skip the spans entirely.
+
+use std::cell::Cell;
+use std::fmt::Write as _;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+
+/// Process-wide counter handing out a stable, small id to each thread that
+/// ever opens a span, so overlapping work (e.g. `ThreadedExecutor`'s worker
+/// pool) shows up as distinct rows in `chrome://tracing` instead of all
+/// collapsing onto one.
+static NEXT_TID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static TID: Cell<u64> = Cell::new(0);
+}
+
+fn current_tid() -> u64 {
+    TID.with(|tid| {
+        let current = tid.get();
+        if current != 0 {
+            return current;
+        }
+        let assigned = NEXT_TID.fetch_add(1, Ordering::Relaxed);
+        tid.set(assigned);
+        assigned
+    })
+}
+
+/// Escape `s` for embedding in a JSON string literal (the caller supplies
+/// the surrounding quotes). Unlike `{:?}`, this produces valid JSON for
+/// every `char`, matching how `chrome://tracing`/Perfetto expect control
+/// characters to be escaped (`\u00XX`, not Rust's `\u{XX}`).
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+struct Event {
+    name: String,
+    cat: &'static str,
+    ph: char,
+    ts_micros: u64,
+    tid: u64,
+    args: Option<String>,
+}
+
+/// A single trace output. Spans record their start/end timestamp relative
+/// to when the tracer was created and are flushed to disk on `write`.
+pub struct Tracer {
+    start: Instant,
+    events: Mutex<Vec<Event>>,
+    out_path: String,
+}
+
+impl Tracer {
+    fn new(out_path: String) -> Self {
+        Tracer {
+            start: Instant::now(),
+            events: Mutex::new(Vec::new()),
+            out_path,
+        }
+    }
+
+    fn record(&self, name: &str, cat: &'static str, ph: char, args: Option<String>) {
+        let ts_micros = self.start.elapsed().as_micros() as u64;
+        self.events.lock().unwrap().push(Event {
+            name: name.to_owned(),
+            cat,
+            ph,
+            ts_micros,
+            tid: current_tid(),
+            args,
+        });
+    }
+
+    /// Begin a duration span covering `name`; it ends when the returned
+    /// `Span` is dropped. `detail` (e.g. a component or file name) is
+    /// attached as the event's `args`, if given.
+    pub fn span<'t>(&'t self, name: &str, cat: &'static str, detail: Option<&str>) -> Span<'t> {
+        self.record(name, cat, 'B', detail.map(|d| d.to_owned()));
+        Span {
+            tracer: self,
+            name: name.to_owned(),
+            cat,
+        }
+    }
+
+    /// Serialize the recorded events to `out_path` as Chrome-tracing JSON.
+    pub fn write(&self) -> std::io::Result<()> {
+        let events = self.events.lock().unwrap();
+        let mut json = String::from("[");
+        for (i, e) in events.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"{}\",\"ts\":{},\"pid\":0,\"tid\":{}",
+                escape_json(&e.name),
+                escape_json(e.cat),
+                e.ph,
+                e.ts_micros,
+                e.tid
+            );
+            if let Some(detail) = &e.args {
+                let _ = write!(
+                    json,
+                    ",\"args\":{{\"detail\":\"{}\"}}",
+                    escape_json(detail)
+                );
+            }
+            json.push('}');
+        }
+        json.push(']');
+        fs::write(&self.out_path, json)
+    }
+}
+
+/// A span opened by `Tracer::span`; closes (emits the matching "E" event)
+/// on drop.
+pub struct Span<'t> {
+    tracer: &'t Tracer,
+    name: String,
+    cat: &'static str,
+}
+
+impl<'t> Drop for Span<'t> {
+    fn drop(&mut self) {
+        self.tracer.record(&self.name, self.cat, 'E', None);
+    }
+}
+
+lazy_static! {
+    static ref GLOBAL: Option<Tracer> = std::env::var("RUSTUP_PROFILE").ok().map(Tracer::new);
+}
+
+/// The process-wide tracer, if `RUSTUP_PROFILE` was set; `None` means
+/// tracing is disabled and callers should skip opening spans.
+pub fn global() -> Option<&'static Tracer> {
+    GLOBAL.as_ref()
+}
+
+/// Flush the global tracer's trace file, if tracing is enabled. Each call
+/// rewrites the file with every event recorded so far, so it may be called
+/// more than once (e.g. after each install via `FlushGuard`).
+pub fn flush() -> std::io::Result<()> {
+    match global() {
+        Some(tracer) => tracer.write(),
+        None => Ok(()),
+    }
+}
+
+/// Flushes the global tracer on drop. Since this crate has no single
+/// process-exit hook to call `flush` from, callers that drive a top-level
+/// operation worth profiling (e.g. an install) should hold one of these for
+/// its duration, so the trace file is written even if the operation returns
+/// early via `?`.
+#[must_use]
+pub struct FlushGuard(());
+
+/// Starts guarding the global tracer, if tracing is enabled; flushes it when
+/// the returned guard is dropped.
+pub fn flush_on_drop() -> FlushGuard {
+    FlushGuard(())
+}
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        let _ = flush();
+    }
+}